@@ -0,0 +1,47 @@
+// Copyright 2020 CoreOS, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Command-line argument parsing for `coreos-installer install`.
+
+use error_chain::bail;
+use structopt::StructOpt;
+
+use crate::errors::*;
+#[cfg(target_arch = "s390x")]
+use crate::s390x::DiskLayout;
+
+/// Install Fedora CoreOS or RHEL CoreOS to a target disk
+#[derive(Debug, StructOpt)]
+pub struct InstallConfig {
+    /// Destination device
+    pub device: String,
+
+    /// DASD disk layout to use (s390x only)
+    ///
+    /// # Arguments
+    /// * `cdl` - Compatible Disk Layout (default); partitioned via a VTOC
+    /// * `ldl` - Linux Disk Layout; a single partition, no VTOC
+    #[cfg(target_arch = "s390x")]
+    #[structopt(long, default_value = "cdl", parse(try_from_str = parse_dasd_layout))]
+    pub dasd_layout: DiskLayout,
+}
+
+#[cfg(target_arch = "s390x")]
+fn parse_dasd_layout(s: &str) -> Result<DiskLayout> {
+    match s {
+        "cdl" => Ok(DiskLayout::Cdl),
+        "ldl" => Ok(DiskLayout::Ldl),
+        _ => bail!("invalid DASD layout '{}': expected 'cdl' or 'ldl'", s),
+    }
+}
@@ -14,10 +14,8 @@
 
 use error_chain::bail;
 use gptman::GPT;
-use std::fs::{read_to_string, File};
+use std::fs::{read_to_string, File, OpenOptions};
 use std::io::{self, copy, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
-use std::num::NonZeroU32;
-use std::os::unix::io::AsRawFd;
 use std::path::Path;
 use std::process::{Command, Stdio};
 
@@ -29,6 +27,10 @@ use crate::util::*;
 
 use crate::runcmd;
 
+mod vtoc;
+pub(crate) use vtoc::DiskLayout;
+use vtoc::{Extent, Geometry, Vtoc, VtocEntry};
+
 /////////////////////////////////////////////////////////////////////////////
 // IBM DASD Support
 /////////////////////////////////////////////////////////////////////////////
@@ -41,8 +43,13 @@ struct Range {
 }
 
 pub fn prepare_dasd(config: &InstallConfig) -> Result<()> {
-    low_level_format(&config.device)?;
-    if is_invalid(&config.device)? {
+    if dasd_type(&config.device)? == DasdType::Fba {
+        // FBA DASDs have no low-level format step and no CDL/VTOC to validate
+        eprintln!("Skipping low-level format for FBA DASD {}", &config.device);
+        return Ok(());
+    }
+    low_level_format(&config.device, config.dasd_layout)?;
+    if config.dasd_layout == DiskLayout::Cdl && is_invalid(&config.device)? {
         eprintln!("Disk {} is invalid, formatting", &config.device);
         default_format(&config.device)?
     }
@@ -54,15 +61,46 @@ pub fn image_copy_s390x(
     source: &mut dyn Read,
     dest_file: &mut File,
     dest_path: &Path,
-    _saved: Option<&SavedPartitions>,
+    saved: Option<&SavedPartitions>,
+    dasd_layout: DiskLayout,
 ) -> Result<()> {
-    let (ranges, partitions) = partition_ranges(first_mb, dest_file)?;
-    make_partitions(
-        dest_path
-            .to_str()
-            .chain_err(|| format!("couldn't encode path {}", dest_path.display()))?,
-        &partitions,
-    )?;
+    let dasd = dest_path
+        .to_str()
+        .chain_err(|| format!("couldn't encode path {}", dest_path.display()))?;
+    let ranges = match (dasd_type(dasd)?, dasd_layout) {
+        (DasdType::Fba, _) => {
+            if saved.is_some() {
+                bail!("saving partitions is not supported on FBA DASDs");
+            }
+            // FBA behaves like an ordinary flat LBA disk: the source
+            // image's GPT is the on-disk partition table too, so copy it
+            // over verbatim instead of generating one like CDL/VTOC does
+            dest_file
+                .seek(SeekFrom::Start(0))
+                .chain_err(|| "seeking to start of disk")?;
+            dest_file
+                .write_all(first_mb)
+                .chain_err(|| "writing GPT to disk")?;
+            partition_ranges_fba(first_mb, dest_file)?
+        }
+        (DasdType::Eckd, DiskLayout::Ldl) => {
+            if saved.is_some() {
+                bail!("saving partitions is not supported with the LDL disk layout");
+            }
+            vec![partition_range_ldl(first_mb, dest_file)?]
+        }
+        (DasdType::Eckd, _) => {
+            let (ranges, mut entries) =
+                partition_ranges_eckd(first_mb, dest_file, saved.is_none())?;
+            if let Some(saved) = saved {
+                let geometry = Geometry::read(dest_file)?;
+                check_saved_collisions(&ranges, saved, &geometry)?;
+                entries.extend(saved_partition_entries(saved, &geometry));
+            }
+            make_partitions(dest_file, &entries)?;
+            ranges
+        }
+    };
 
     // copy each partition
     eprintln!("Installing to {}", dest_path.display());
@@ -97,20 +135,38 @@ pub fn image_copy_s390x(
     // close out the stream
     copy(source, sink).chain_err(|| "reading remainder of stream")?;
     dest.flush().chain_err(|| "flushing data to disk")?;
+    let dest_file = dest.into_inner().chain_err(|| "flushing data to disk")?;
+
+    if let Some(saved) = saved {
+        saved
+            .write(dest_file)
+            .chain_err(|| "restoring saved partitions")?;
+    }
 
     Ok(())
 }
 
-/// Generate partition table entries and byte ranges to copy
-fn partition_ranges(header: &[u8], device: &mut File) -> Result<(Vec<Range>, Vec<String>)> {
-    let bytes_per_block: u64 = get_sector_size(device)?.get().into();
-    let blocks_per_track: u64 = get_sectors_per_track(device)?.get().into();
+/// Generate VTOC entries and byte ranges to copy, for the track-and-cylinder
+/// CDL layout used by ECKD DASDs
+///
+/// # Arguments
+/// * `header` - first MiB of the source image, containing its GPT
+/// * `device` - open handle to the destination dasd device
+/// * `extend_last` - extend the last partition to the end of the disk
+fn partition_ranges_eckd(
+    header: &[u8],
+    device: &mut File,
+    extend_last: bool,
+) -> Result<(Vec<Range>, Vec<VtocEntry>)> {
+    let geometry = Geometry::read(device)?;
+    let bytes_per_block = geometry.bytes_per_block;
+    let blocks_per_track: u64 = geometry.sectors_per_track.get().into();
 
     let gpt = GPT::read_from(&mut Cursor::new(header), bytes_per_block)
         .chain_err(|| "reading GPT of source image")?;
 
     let mut ranges = Vec::new();
-    let mut partitions = Vec::new();
+    let mut vtoc_entries = Vec::new();
     let mut start_track: u64 = 2; // the first 2 tracks of the ECKD DASD are reserved
     let entries = || gpt.iter().filter(|(_, pt)| pt.is_used());
     let (last_partition, _) = entries()
@@ -120,6 +176,15 @@ fn partition_ranges(header: &[u8], device: &mut File) -> Result<(Vec<Range>, Vec
     for (i, pt) in entries() {
         let blocks = pt.ending_lba - pt.starting_lba + 1;
         let end_track = start_track + (blocks + blocks_per_track - 1) / blocks_per_track - 1;
+        // fdasd's "last" keyword extended a partition to the end of the
+        // disk; do the same so the final partition isn't short a few
+        // trailing tracks that don't make up a whole block; not done when
+        // saved partitions have to stay outside the new partitions' extents
+        let end_track = if extend_last && i == last_partition {
+            geometry.total_tracks() - 1
+        } else {
+            end_track
+        };
 
         ranges.push(Range {
             in_offset: pt.starting_lba * bytes_per_block,
@@ -127,16 +192,185 @@ fn partition_ranges(header: &[u8], device: &mut File) -> Result<(Vec<Range>, Vec
             length: blocks * bytes_per_block,
         });
 
-        if i == last_partition {
-            partitions.push(format!("[{}, last, native]", start_track));
-        } else {
-            partitions.push(format!("[{}, {}, native]", start_track, end_track));
-        };
+        vtoc_entries.push(VtocEntry {
+            extent: Extent {
+                start: geometry.cchh(start_track),
+                end: geometry.cchh(end_track),
+            },
+            kind: fdasd_type(&pt.partition_type_guid).to_string(),
+        });
         start_track = end_track + 1;
     }
     // partitions should be in offset order, but just to be sure
     ranges.sort_unstable_by_key(|r| r.in_offset);
-    Ok((ranges, partitions))
+    Ok((ranges, vtoc_entries))
+}
+
+/// GPT partition type GUIDs that `fdasd` has a dedicated keyword for,
+/// rather than the generic `native`.
+const LINUX_SWAP_GUID: [u8; 16] = [
+    0x6d, 0xfd, 0x57, 0x06, 0xab, 0xa4, 0xc4, 0x43, 0x84, 0xe5, 0x09, 0x33, 0xc8, 0x4b, 0x4f, 0x4f,
+]; // 0657FD6D-A4AB-43C4-84E5-0933C84B4F4F
+const LINUX_RAID_GUID: [u8; 16] = [
+    0x0f, 0x88, 0x9d, 0xa1, 0xb8, 0x08, 0x9d, 0x4e, 0x8d, 0x0d, 0x3c, 0xf1, 0x4c, 0xf9, 0xc5, 0x5e,
+]; // A19D880F-08B8-4E9D-8D0D-3CF14CF9C55E
+const LINUX_LVM_GUID: [u8; 16] = [
+    0x79, 0xd3, 0xd6, 0xe6, 0x07, 0xf5, 0xc2, 0x44, 0xa2, 0x3c, 0x23, 0x8f, 0x2a, 0x3d, 0xf9, 0x28,
+]; // E6D6D379-F507-44C2-A23C-238F2A3DF928
+const LINUX_GPFS_GUID: [u8; 16] = [
+    0x90, 0xfc, 0xaf, 0x37, 0x7d, 0xef, 0x96, 0x4e, 0x91, 0xc3, 0x2d, 0x7a, 0xe0, 0x55, 0xb1, 0x74,
+]; // 37AFFC90-EF7D-4E96-91C3-2D7AE055B174
+
+/// Map a GPT partition type GUID to the `fdasd` type keyword it
+/// corresponds to, defaulting to `native` for anything else.
+///
+/// # Arguments
+/// * `guid` - GPT partition type GUID, as raw bytes
+fn fdasd_type(guid: &[u8; 16]) -> &'static str {
+    match *guid {
+        LINUX_SWAP_GUID => "swap",
+        LINUX_RAID_GUID => "raid",
+        LINUX_LVM_GUID => "lvm",
+        LINUX_GPFS_GUID => "gpfs",
+        _ => "native",
+    }
+}
+
+/// Generate byte ranges to copy for an FBA DASD: a flat LBA scheme with no
+/// reserved tracks and no CDL/VTOC partition table to generate
+///
+/// # Arguments
+/// * `header` - first MiB of the source image, containing its GPT
+/// * `device` - open handle to the destination dasd device
+fn partition_ranges_fba(header: &[u8], device: &mut File) -> Result<Vec<Range>> {
+    let bytes_per_block: u64 = get_sector_size(device)?.get().into();
+
+    let gpt = GPT::read_from(&mut Cursor::new(header), bytes_per_block)
+        .chain_err(|| "reading GPT of source image")?;
+
+    let mut ranges: Vec<Range> = gpt
+        .iter()
+        .filter(|(_, pt)| pt.is_used())
+        .map(|(_, pt)| {
+            let in_offset = pt.starting_lba * bytes_per_block;
+            Range {
+                in_offset,
+                // FBA has no reserved tracks or track-rounded offsets: a
+                // partition lands at the same LBA on the target as on the
+                // source image
+                out_offset: in_offset,
+                length: (pt.ending_lba - pt.starting_lba + 1) * bytes_per_block,
+            }
+        })
+        .collect();
+    // ranges should be in offset order, but just to be sure
+    ranges.sort_unstable_by_key(|r| r.in_offset);
+    Ok(ranges)
+}
+
+/// Generate the single byte range to copy for an LDL disk: there's no
+/// VTOC, so the image's one partition goes right after the volume label
+/// with no reserved tracks or fdasd config to generate.
+///
+/// # Arguments
+/// * `header` - first MiB of the source image, containing its GPT
+/// * `device` - open handle to the destination dasd device
+fn partition_range_ldl(header: &[u8], device: &mut File) -> Result<Range> {
+    // blocks 0-1 hold IPL records, block 2 the volume label; LDL data
+    // starts immediately after that
+    const LDL_DATA_BLOCK: u64 = 3;
+
+    let bytes_per_block: u64 = get_sector_size(device)?.get().into();
+
+    let gpt = GPT::read_from(&mut Cursor::new(header), bytes_per_block)
+        .chain_err(|| "reading GPT of source image")?;
+    let mut entries = gpt.iter().filter(|(_, pt)| pt.is_used());
+    let (_, pt) = entries
+        .next()
+        .chain_err(|| "source image has no partitions")?;
+    if entries.next().is_some() {
+        bail!("LDL disk layout only supports a single partition");
+    }
+
+    Ok(Range {
+        in_offset: pt.starting_lba * bytes_per_block,
+        out_offset: LDL_DATA_BLOCK * bytes_per_block,
+        length: (pt.ending_lba - pt.starting_lba + 1) * bytes_per_block,
+    })
+}
+
+/// Bail out if any partition of the new image would land on top of a
+/// partition we're supposed to be preserving
+///
+/// # Arguments
+/// * `ranges` - byte ranges the new image's partitions will occupy
+/// * `saved` - partitions being preserved across the install
+/// * `geometry` - disk geometry, to translate LBAs to byte offsets
+fn check_saved_collisions(
+    ranges: &[Range],
+    saved: &SavedPartitions,
+    geometry: &Geometry,
+) -> Result<()> {
+    for pt in saved.get_partitions() {
+        let saved_start = pt.starting_lba * geometry.bytes_per_block;
+        let saved_end = (pt.ending_lba + 1) * geometry.bytes_per_block;
+        for range in ranges {
+            if saved_start < range.out_offset + range.length && saved_end > range.out_offset {
+                bail!(
+                    "saved partition at {}-{} collides with new partition at {}-{}",
+                    saved_start,
+                    saved_end,
+                    range.out_offset,
+                    range.out_offset + range.length
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Generate VTOC entries covering the saved partitions, so they survive
+/// as real partitions after the new VTOC is written
+///
+/// # Arguments
+/// * `saved` - partitions being preserved across the install
+/// * `geometry` - disk geometry, to translate LBAs to CCHH addresses
+fn saved_partition_entries(saved: &SavedPartitions, geometry: &Geometry) -> Vec<VtocEntry> {
+    let blocks_per_track: u64 = geometry.sectors_per_track.get().into();
+    saved
+        .get_partitions()
+        .map(|pt| VtocEntry {
+            extent: Extent {
+                start: geometry.cchh(pt.starting_lba / blocks_per_track),
+                end: geometry.cchh(pt.ending_lba / blocks_per_track),
+            },
+            kind: fdasd_type(&pt.partition_type_guid).to_string(),
+        })
+        .collect()
+}
+
+/// DASD architecture, as reported by the device's discipline
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DasdType {
+    /// Extended Count Key Data: tracks and cylinders, CDL/LDL label, needs
+    /// a low-level format
+    Eckd,
+    /// Fixed Block Architecture: behaves like an ordinary flat LBA disk
+    Fba,
+}
+
+/// Determine whether a DASD is ECKD or FBA
+///
+/// # Arguments
+/// * `dasd` - dasd device, i.e. smth like /dev/dasda
+fn dasd_type(dasd: &str) -> Result<DasdType> {
+    let id = bus_id(dasd)?;
+    let path = format!("/sys/bus/ccw/devices/{}/discipline", id);
+    let contents = read_to_string(&path).chain_err(|| format!("reading {}", path))?;
+    Ok(match contents.trim_end() {
+        "FBA" => DasdType::Fba,
+        _ => DasdType::Eckd,
+    })
 }
 
 /// Get disk bus id
@@ -167,10 +401,9 @@ fn bus_id(dasd: &str) -> Result<String> {
 /// # Arguments
 /// * `dasd` - dasd device, i.e. smth like /dev/dasda
 fn is_formatted(dasd: &str) -> Result<bool> {
-    let id = bus_id(dasd)?;
-    let path = format!("/sys/bus/ccw/devices/{}/status", id);
-    let contents = read_to_string(&path).chain_err(|| format!("reading {}", path))?;
-    Ok(!contents.contains("unformatted"))
+    let mut file = File::open(dasd).chain_err(|| format!("opening {}", dasd))?;
+    let geometry = Geometry::read(&file)?;
+    Vtoc::is_formatted(&mut file, &geometry)
 }
 
 /// Check if disk is valid or not
@@ -178,28 +411,33 @@ fn is_formatted(dasd: &str) -> Result<bool> {
 /// # Arguments
 /// * `dasd` - dasd device, i.e. smth like /dev/dasda
 fn is_invalid(dasd: &str) -> Result<bool> {
-    let mut cmd = Command::new("fdasd");
-    // we're looking for a hardcoded string in the output
-    cmd.env("LC_ALL", "C").arg("-p").arg(dasd);
-    Ok(cmd_output(&mut cmd)?.contains("disk label block is invalid"))
+    let mut file = File::open(dasd).chain_err(|| format!("opening {}", dasd))?;
+    let geometry = Geometry::read(&file)?;
+    Ok(!Vtoc::is_valid(&mut file, &geometry)?)
 }
 
 /// Perform low-level format. This step is necessary before any further disk usage
 ///
 /// # Arguments
 /// * `dasd` - dasd device, i.e. smth like /dev/dasda
-fn low_level_format(dasd: &str) -> Result<()> {
+/// * `layout` - CDL (multi-partition, with VTOC) or LDL (single partition, no VTOC)
+fn low_level_format(dasd: &str, layout: DiskLayout) -> Result<()> {
     if is_formatted(dasd)? {
         eprintln!("Skipping low-level format for {}", dasd);
         return Ok(());
     }
+    let layout_arg = match layout {
+        DiskLayout::Cdl => "cdl",
+        DiskLayout::Ldl => "ldl",
+        DiskLayout::Cms => bail!("CMS disk layout is not supported for installation"),
+    };
     eprintln!("Performing low-level format for {}", dasd);
     runcmd!(
         "dasdfmt",
         "--blocksize",
         "4096",
         "--disk_layout",
-        "cdl",
+        layout_arg,
         "--mode",
         "full",
         "-y",
@@ -210,94 +448,44 @@ fn low_level_format(dasd: &str) -> Result<()> {
     Ok(())
 }
 
-/// Format disk and create partitions
+/// Write a fresh VTOC to a CDL DASD, replacing whatever was there before
 ///
 /// # Arguments
-/// * `dasd` - dasd device, i.e. smth like /dev/dasda
-/// * `partitions` - configuration strings
-fn make_partitions(dasd: &str, partitions: &[String]) -> Result<()> {
-    if partitions.len() > 3 {
-        // fdasd silently ignores partitions after the first 3
-        bail!("Can't create {} partitions, maximum 3", partitions.len());
-    }
-    let mut config = partitions.join("\n");
-    config.push('\n');
-    if try_format(dasd, &config).is_err() {
-        default_format(dasd)?;
-        try_format(dasd, &config)?;
+/// * `device` - open handle to the dasd device, i.e. smth like /dev/dasda
+/// * `entries` - partitions to write
+fn make_partitions(device: &mut File, entries: &[VtocEntry]) -> Result<()> {
+    if entries.len() > 3 {
+        // the single-track VTOC we write only has room for a handful of
+        // entries; this is also the most `fdasd` itself ever allowed
+        bail!("Can't create {} partitions, maximum 3", entries.len());
     }
+    let geometry = Geometry::read(device)?;
+    Vtoc::format(device, &geometry, entries).chain_err(|| "writing VTOC")?;
+    udev_settle()?;
     Ok(())
 }
 
-/// If config-based format fails, then we have to perform
-/// an auto-format on the whole disk
+/// Write a default VTOC with a single native partition spanning the whole
+/// disk. This is the native equivalent of `fdasd -a`.
 ///
 /// # Arguments
 /// * `dasd` - dasd device, i.e. smth like /dev/dasda
 fn default_format(dasd: &str) -> Result<()> {
     eprintln!("Auto-partitioning {}", dasd);
-    runcmd!("fdasd", "-a", "-s", dasd).chain_err(|| format!("auto-formatting {} failed", dasd))?;
-    udev_settle()?;
-    Ok(())
-}
-
-/// Format disk using a config file
-///
-/// # Arguments
-/// * `dasd` - dasd device, i.e. smth like /dev/dasda
-/// * `config` - configuration file contents
-fn try_format(dasd: &str, config: &str) -> Result<()> {
-    eprintln!("Partitioning {}", dasd);
-    let mut child = Command::new("fdasd")
-        .arg("-s")
-        .arg("--config")
-        .arg("/dev/stdin")
-        .arg(dasd)
-        .stdin(Stdio::piped())
-        .spawn()
-        .chain_err(|| "failed to execute fdasd")?;
-    child
-        .stdin
-        .as_mut()
-        .chain_err(|| "couldn't open fdasd stdin")?
-        .write_all(config.as_bytes())
-        .chain_err(|| "couldn't write fdasd stdin")?;
-    if !child
-        .wait()
-        .chain_err(|| "couldn't wait on fdasd")?
-        .success()
-    {
-        bail!("couldn't format {} based on:\n{}", dasd, config);
-    }
+    let mut file = OpenOptions::new()
+        .write(true)
+        .open(dasd)
+        .chain_err(|| format!("opening {}", dasd))?;
+    let geometry = Geometry::read(&file)?;
+    let entries = [VtocEntry {
+        extent: Extent {
+            start: geometry.cchh(2), // the first 2 tracks are reserved
+            end: geometry.cchh(geometry.total_tracks() - 1),
+        },
+        kind: "native".to_string(),
+    }];
+    Vtoc::format(&mut file, &geometry, &entries)
+        .chain_err(|| format!("auto-formatting {} failed", dasd))?;
     udev_settle()?;
     Ok(())
 }
-
-/// Get the number of sectors per track of a block device.
-fn get_sectors_per_track(file: &File) -> Result<NonZeroU32> {
-    let fd = file.as_raw_fd();
-    let mut geo: ioctl::hd_geometry = Default::default();
-    match unsafe { ioctl::hdio_getgeo(fd, &mut geo) } {
-        Ok(_) => {
-            NonZeroU32::new(geo.sectors.into()).ok_or_else(|| "found sectors/track of zero".into())
-        }
-        Err(e) => Err(Error::with_chain(e, "getting disk geometry")),
-    }
-}
-
-// create unsafe ioctl wrappers
-mod ioctl {
-    use nix::ioctl_read_bad;
-    use std::os::raw::{c_uchar, c_ulong, c_ushort};
-
-    #[repr(C)]
-    #[derive(Debug, Default)]
-    pub struct hd_geometry {
-        pub heads: c_uchar,
-        pub sectors: c_uchar,
-        pub cylinders: c_ushort,
-        pub start: c_ulong,
-    }
-
-    ioctl_read_bad!(hdio_getgeo, 0x0301, hd_geometry);
-}